@@ -0,0 +1,367 @@
+use git2::{BranchType, Repository};
+use std::fmt;
+use std::process::Command;
+
+/// A remote branch, along with the timestamp of the commit it points to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Branch {
+    pub name: String,
+    pub time: i64,
+}
+
+#[derive(Debug)]
+pub enum GitRepoError {
+    Git2(git2::Error),
+    Shell(String),
+}
+
+impl fmt::Display for GitRepoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GitRepoError::Git2(e) => write!(f, "{}", e),
+            GitRepoError::Shell(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for GitRepoError {}
+
+impl From<git2::Error> for GitRepoError {
+    fn from(e: git2::Error) -> Self {
+        GitRepoError::Git2(e)
+    }
+}
+
+/// Abstracts the git operations `create_new_branch` needs, so the branch-selection logic can be
+/// unit-tested against a fake implementation and so users who can't link libgit2 can fall back to
+/// shelling out to the `git` binary.
+pub trait GitRepo {
+    fn remotes(&self) -> Result<Vec<String>, GitRepoError>;
+    fn current_branch(&self) -> Result<String, GitRepoError>;
+    fn remote_branches(&self) -> Result<Vec<Branch>, GitRepoError>;
+    fn find_branch(&self, name: &str) -> Result<Option<Branch>, GitRepoError>;
+    fn create_branch(&self, name: &str, base: &str) -> Result<(), GitRepoError>;
+
+    /// Whether a *local* branch named `name` already exists (as opposed to `find_branch`, which
+    /// only looks at remote-tracking branches).
+    fn local_branch_exists(&self, name: &str) -> Result<bool, GitRepoError>;
+
+    fn checkout(&self, name: &str) -> Result<(), GitRepoError>;
+
+    /// Update remote-tracking refs for `remote_name` so freshly-pushed branches are visible to
+    /// `remote_branches`.
+    fn fetch(&self, remote_name: &str) -> Result<(), GitRepoError>;
+}
+
+/// `GitRepo` backed directly by `git2`/libgit2.
+pub struct Git2Repo {
+    repo: Repository,
+}
+
+impl Git2Repo {
+    pub fn discover(path: &std::path::Path) -> Result<Self, GitRepoError> {
+        Ok(Git2Repo {
+            repo: Repository::discover(path)?,
+        })
+    }
+}
+
+impl GitRepo for Git2Repo {
+    fn remotes(&self) -> Result<Vec<String>, GitRepoError> {
+        let remotes = self.repo.remotes()?;
+        Ok(remotes.iter().filter_map(|r| r.map(String::from)).collect())
+    }
+
+    fn current_branch(&self) -> Result<String, GitRepoError> {
+        let head = self.repo.head()?;
+        Ok(head.name().unwrap_or_default().to_string())
+    }
+
+    fn remote_branches(&self) -> Result<Vec<Branch>, GitRepoError> {
+        let mut branches = Vec::new();
+
+        for b in self.repo.branches(Some(BranchType::Remote))? {
+            let (b, _) = b?;
+            if let Some(name) = b.name()? {
+                let time = b.get().peel_to_commit()?.time().seconds();
+                branches.push(Branch {
+                    name: name.to_string(),
+                    time,
+                });
+            }
+        }
+
+        Ok(branches)
+    }
+
+    fn find_branch(&self, name: &str) -> Result<Option<Branch>, GitRepoError> {
+        Ok(self
+            .remote_branches()?
+            .into_iter()
+            .find(|b| b.name == name))
+    }
+
+    fn create_branch(&self, name: &str, base: &str) -> Result<(), GitRepoError> {
+        let base_branch = self.repo.find_branch(base, BranchType::Remote)?;
+        let commit = base_branch.get().peel_to_commit()?;
+        self.repo.branch(name, &commit, false)?;
+        Ok(())
+    }
+
+    fn local_branch_exists(&self, name: &str) -> Result<bool, GitRepoError> {
+        Ok(self.repo.find_branch(name, BranchType::Local).is_ok())
+    }
+
+    fn checkout(&self, name: &str) -> Result<(), GitRepoError> {
+        let refname = format!("refs/heads/{}", name);
+        let obj = self.repo.revparse_single(&refname)?;
+        self.repo.checkout_tree(&obj, None)?;
+        self.repo.set_head(&refname)?;
+        Ok(())
+    }
+
+    fn fetch(&self, remote_name: &str) -> Result<(), GitRepoError> {
+        let mut remote = self.repo.find_remote(remote_name)?;
+
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(|url, username_from_url, allowed_types| {
+            if allowed_types.contains(git2::CredentialType::SSH_KEY) {
+                git2::Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
+            } else if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+                git2::Cred::credential_helper(&self.repo.config()?, url, username_from_url)
+            } else {
+                git2::Cred::default()
+            }
+        });
+        callbacks.transfer_progress(|stats| {
+            println!(
+                "Fetching {} : {}/{} objects",
+                remote_name,
+                stats.received_objects(),
+                stats.total_objects()
+            );
+            true
+        });
+
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+
+        remote.fetch(&[] as &[&str], Some(&mut fetch_options), None)?;
+
+        Ok(())
+    }
+}
+
+/// `GitRepo` backed by shelling out to the system `git` binary, for platforms where linking
+/// libgit2 is painful.
+pub struct ShellGitRepo {
+    dir: std::path::PathBuf,
+}
+
+impl ShellGitRepo {
+    pub fn discover(dir: &std::path::Path) -> Self {
+        ShellGitRepo {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn git(&self, args: &[&str]) -> Result<String, GitRepoError> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(&self.dir)
+            .output()
+            .map_err(|e| GitRepoError::Shell(e.to_string()))?;
+
+        if !output.status.success() {
+            return Err(GitRepoError::Shell(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+impl GitRepo for ShellGitRepo {
+    fn remotes(&self) -> Result<Vec<String>, GitRepoError> {
+        let out = self.git(&["remote"])?;
+        Ok(out.lines().map(String::from).collect())
+    }
+
+    fn current_branch(&self) -> Result<String, GitRepoError> {
+        self.git(&["rev-parse", "--abbrev-ref", "HEAD"])
+    }
+
+    fn remote_branches(&self) -> Result<Vec<Branch>, GitRepoError> {
+        let out = self.git(&[
+            "for-each-ref",
+            "--format=%(refname:short) %(committerdate:unix)",
+            "refs/remotes",
+        ])?;
+
+        Ok(out
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.rsplitn(2, ' ');
+                let time = parts.next()?.parse().ok()?;
+                let name = parts.next()?.to_string();
+                Some(Branch { name, time })
+            })
+            .collect())
+    }
+
+    fn find_branch(&self, name: &str) -> Result<Option<Branch>, GitRepoError> {
+        Ok(self
+            .remote_branches()?
+            .into_iter()
+            .find(|b| b.name == name))
+    }
+
+    fn create_branch(&self, name: &str, base: &str) -> Result<(), GitRepoError> {
+        self.git(&["branch", name, base])?;
+        Ok(())
+    }
+
+    fn local_branch_exists(&self, name: &str) -> Result<bool, GitRepoError> {
+        let refname = format!("refs/heads/{}", name);
+        Ok(self.git(&["show-ref", "--verify", "--quiet", &refname]).is_ok())
+    }
+
+    fn checkout(&self, name: &str) -> Result<(), GitRepoError> {
+        // Plain `git checkout` would detach HEAD on a remote-qualified name, so only accept
+        // local branches, like `Git2Repo::checkout` does.
+        if !self.local_branch_exists(name)? {
+            return Err(GitRepoError::Shell(format!(
+                "`{}` is not a local branch",
+                name
+            )));
+        }
+        self.git(&["checkout", name])?;
+        Ok(())
+    }
+
+    fn fetch(&self, remote_name: &str) -> Result<(), GitRepoError> {
+        self.git(&["fetch", remote_name])?;
+        Ok(())
+    }
+}
+
+/// Which `GitRepo` implementation to use, selected from config or the
+/// `REDMINE_NEW_BRANCH_GIT_BACKEND` env var (`"git2"` or `"shell"`, defaults to `"git2"`).
+pub fn backend_from_name(name: &str, path: &std::path::Path) -> Result<Box<dyn GitRepo>, GitRepoError> {
+    match name {
+        "shell" => Ok(Box::new(ShellGitRepo::discover(path))),
+        _ => Ok(Box::new(Git2Repo::discover(path)?)),
+    }
+}
+
+/// An in-memory `GitRepo` used by tests (here and in `main`) to exercise branch-selection and
+/// creation logic without touching the filesystem. Records `create_branch`/`checkout` calls so
+/// tests can assert which one happened, and rejects `checkout` of a name that isn't a known
+/// local branch (`existing_local_branches`, or one created via `create_branch`) the same way
+/// `Git2Repo`/`ShellGitRepo` would fail on a remote-qualified name like `"origin/foo"`.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeGitRepo {
+    pub branches: Vec<Branch>,
+    pub current_branch: String,
+    pub existing_local_branches: Vec<String>,
+    pub created_branches: std::cell::RefCell<Vec<(String, String)>>,
+    pub checked_out: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl GitRepo for FakeGitRepo {
+    fn remotes(&self) -> Result<Vec<String>, GitRepoError> {
+        Ok(vec!["origin".to_string()])
+    }
+
+    fn current_branch(&self) -> Result<String, GitRepoError> {
+        if self.current_branch.is_empty() {
+            Ok("refs/heads/master".to_string())
+        } else {
+            Ok(self.current_branch.clone())
+        }
+    }
+
+    fn remote_branches(&self) -> Result<Vec<Branch>, GitRepoError> {
+        Ok(self.branches.clone())
+    }
+
+    fn find_branch(&self, name: &str) -> Result<Option<Branch>, GitRepoError> {
+        Ok(self.branches.iter().find(|b| b.name == name).cloned())
+    }
+
+    fn create_branch(&self, name: &str, base: &str) -> Result<(), GitRepoError> {
+        self.created_branches
+            .borrow_mut()
+            .push((name.to_string(), base.to_string()));
+        Ok(())
+    }
+
+    fn local_branch_exists(&self, name: &str) -> Result<bool, GitRepoError> {
+        Ok(self.existing_local_branches.iter().any(|b| b == name)
+            || self.created_branches.borrow().iter().any(|(n, _)| n == name))
+    }
+
+    fn checkout(&self, name: &str) -> Result<(), GitRepoError> {
+        if !self.local_branch_exists(name)? {
+            return Err(GitRepoError::Shell(format!(
+                "cannot checkout unknown local branch `{}` (remote-qualified names must be \
+                 turned into a local tracking branch first)",
+                name
+            )));
+        }
+        self.checked_out.borrow_mut().push(name.to_string());
+        Ok(())
+    }
+
+    fn fetch(&self, _remote_name: &str) -> Result<(), GitRepoError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fake_repo_lists_remote_branches() {
+        let fake = FakeGitRepo {
+            branches: vec![
+                Branch {
+                    name: "origin/wab-8.1".to_string(),
+                    time: 100,
+                },
+                Branch {
+                    name: "origin/master".to_string(),
+                    time: 50,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let names: Vec<String> = fake
+            .remote_branches()
+            .unwrap()
+            .into_iter()
+            .map(|b| b.name)
+            .collect();
+
+        assert_eq!(names, vec!["origin/wab-8.1", "origin/master"]);
+    }
+
+    #[test]
+    fn fake_repo_finds_existing_branch() {
+        let fake = FakeGitRepo {
+            branches: vec![Branch {
+                name: "origin/wab-8.1".to_string(),
+                time: 100,
+            }],
+            ..Default::default()
+        };
+
+        assert!(fake.find_branch("origin/wab-8.1").unwrap().is_some());
+        assert!(fake.find_branch("origin/wab-9.0").unwrap().is_none());
+    }
+}
@@ -1,11 +1,13 @@
 use dialoguer::{theme::ColorfulTheme, Select};
-use git2::{BranchType, Repository, RepositoryState};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::str;
 use structopt::StructOpt;
 
+mod git_repo;
+use git_repo::{backend_from_name, GitRepo, GitRepoError};
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "redmine-new-branch",
@@ -24,14 +26,65 @@ struct Opt {
     /// Set redmine ticket
     #[structopt(short, long)]
     ticket: i64,
+
+    /// Interactively pick the base branch among all remote branches, sorted by most recent
+    /// commit, instead of defaulting to master when no maintenance or parent branch is found
+    #[structopt(long)]
+    pick_base: bool,
+
+    /// Write the created branch name back to the Redmine ticket: always as a note, and also as
+    /// a custom field when `branch_custom_field_id` is set in the config file
+    #[structopt(long, alias = "comment")]
+    notify: bool,
+
+    /// Don't fetch the remote before resolving source/target branches (for offline use)
+    #[structopt(long)]
+    no_fetch: bool,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 struct MyConfig {
     version: u8,
     api_key: String,
+
+    /// Template used to build the branch name, e.g. "rd-{id}-{trigram}-{version}-{subject}".
+    /// Recognized tokens: {id}, {trigram}, {version}, {subject}, {assignee} and
+    /// {custom:<field name>}. Empty means "use the built-in default".
+    #[serde(default)]
+    branch_template: String,
+
+    /// Maximum length of the cleaned-up subject slug. 0 means "use the built-in default".
+    #[serde(default)]
+    subject_max_len: usize,
+
+    /// Separator used to join words in the subject/assignee slugs. Empty means "-".
+    #[serde(default)]
+    separator: String,
+
+    /// Template used to build the trigram, e.g. "{f1}{l2}". Recognized tokens: {f1} (first
+    /// letter of the assignee's first name) and {l2} (first two letters of their last name).
+    /// Empty means "use the built-in default".
+    #[serde(default)]
+    trigram_format: String,
+
+    /// Which `GitRepo` backend to use: "git2" (default) or "shell" to shell out to the `git`
+    /// binary instead of linking libgit2. Overridden by the `REDMINE_NEW_BRANCH_GIT_BACKEND`
+    /// env var.
+    #[serde(default)]
+    git_backend: String,
+
+    /// Id of the Redmine custom field to set to the created branch name when `--notify` is
+    /// used. 0 disables the custom field update (only the note is posted).
+    #[serde(default)]
+    branch_custom_field_id: u32,
 }
 
+const DEFAULT_BRANCH_TEMPLATE: &str = "rd-{id}-{trigram}-{version}-{subject}";
+const DEFAULT_SEPARATOR: &str = "-";
+const DEFAULT_SUBJECT_MAX_LEN: usize = 80;
+const DEFAULT_TRIGRAM_FORMAT: &str = "{f1}{l2}";
+const GIT_BACKEND_ENV_VAR: &str = "REDMINE_NEW_BRANCH_GIT_BACKEND";
+
 #[derive(Serialize, Deserialize, Debug)]
 struct IdProperty {
     id: i32,
@@ -63,41 +116,91 @@ impl Issue {
         &self.fixed_version.name[..3]
     }
 
-    fn cleanup_subject(subject: &String) -> String {
+    fn cleanup_subject(subject: &str, separator: &str, max_len: usize) -> String {
         let mut subject = subject
             .trim()
-            .replace(" ", "-")
+            .replace(" ", separator)
             .replace(":", "=")
             .to_lowercase();
 
-        // Replace multiple -- by only one -
-        let re_multiple_dash = Regex::new(r"-+").unwrap();
+        // Replace multiple separators by only one
+        let re_multiple_sep = Regex::new(&format!("{}+", regex::escape(separator))).unwrap();
         let re_forbidden_char = Regex::new(r#"[\[\]"'\)\()]*"#).unwrap();
 
-        subject = re_multiple_dash.replace_all(&subject, "-").to_string();
+        subject = re_multiple_sep.replace_all(&subject, separator).to_string();
         subject = re_forbidden_char.replace_all(&subject, "").to_string();
         // Replace "à" by "a", "é" by "e" ...
         subject = diacritics::remove_diacritics(&subject);
 
+        if max_len > 0 && subject.chars().count() > max_len {
+            subject = subject.chars().take(max_len).collect();
+            subject = subject.trim_end_matches(separator).to_string();
+        }
+
         subject
     }
 
-    fn get_branch_name(&self) -> String {
+    fn trigram(&self, trigram_format: &str) -> String {
         let v: Vec<&str> = self.assigned_to.name.split(' ').collect();
 
         if v.len() < 2 {
             panic!("Unable to read trigram")
         }
 
-        let subject = Issue::cleanup_subject(&self.subject);
+        trigram_format
+            .replace("{f1}", &v[0][..1])
+            .replace("{l2}", &v[1][..2])
+            .to_lowercase()
+    }
 
-        format!(
-            "rd-{number}-{trigram}-{version}-{subject}",
-            number = self.id,
-            subject = subject,
-            version = &self.target_version(),
-            trigram = format!("{}{}", &v[0][..1], &v[1][..2]).to_lowercase()
-        )
+    fn custom_field(&self, name: &str) -> String {
+        self.custom_fields
+            .iter()
+            .find(|f| f.name == name)
+            .and_then(|f| f.value.clone())
+            .unwrap_or_default()
+    }
+
+    fn get_branch_name(&self, cfg: &MyConfig) -> String {
+        let template = if cfg.branch_template.is_empty() {
+            DEFAULT_BRANCH_TEMPLATE
+        } else {
+            &cfg.branch_template
+        };
+        let separator = if cfg.separator.is_empty() {
+            DEFAULT_SEPARATOR
+        } else {
+            &cfg.separator
+        };
+        let subject_max_len = if cfg.subject_max_len == 0 {
+            DEFAULT_SUBJECT_MAX_LEN
+        } else {
+            cfg.subject_max_len
+        };
+        let trigram_format = if cfg.trigram_format.is_empty() {
+            DEFAULT_TRIGRAM_FORMAT
+        } else {
+            &cfg.trigram_format
+        };
+
+        let subject = Issue::cleanup_subject(&self.subject, separator, subject_max_len);
+
+        let rendered = template
+            .replace("{id}", &self.id.to_string())
+            .replace("{trigram}", &self.trigram(trigram_format))
+            .replace("{version}", self.target_version())
+            .replace("{subject}", &subject)
+            .replace(
+                "{assignee}",
+                &self.assigned_to.name.to_lowercase().replace(' ', separator),
+            );
+
+        let re_custom = Regex::new(r"\{custom:([^}]+)\}").unwrap();
+        re_custom
+            .replace_all(&rendered, |caps: &regex::Captures| {
+                self.custom_field(&caps[1])
+            })
+            .to_string()
     }
 }
 
@@ -107,7 +210,7 @@ struct Ticket {
 }
 
 fn read_issue(body: &str) -> serde_json::Result<Ticket> {
-    serde_json::from_str(&body)
+    serde_json::from_str(body)
 }
 
 fn get_ticket_body(ticket: i64, key: String) -> Result<String, reqwest::Error> {
@@ -129,147 +232,334 @@ fn get_ticket_body(ticket: i64, key: String) -> Result<String, reqwest::Error> {
         .text()
 }
 
-fn create_new_branch(ticket: Ticket) -> Result<(), git2::Error> {
-    let path = env::current_dir().unwrap();
-    let repo = Repository::discover(path)?;
+#[derive(Serialize, Debug)]
+struct IssueUpdateCustomField {
+    id: u32,
+    value: String,
+}
+
+#[derive(Serialize, Debug)]
+struct IssueUpdate {
+    notes: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    custom_fields: Vec<IssueUpdateCustomField>,
+}
+
+#[derive(Serialize, Debug)]
+struct IssueUpdatePayload {
+    issue: IssueUpdate,
+}
+
+fn build_issue_update_payload(notes: &str, branch_name: &str, custom_field_id: u32) -> IssueUpdatePayload {
+    let custom_fields = if custom_field_id > 0 {
+        vec![IssueUpdateCustomField {
+            id: custom_field_id,
+            value: branch_name.to_string(),
+        }]
+    } else {
+        vec![]
+    };
 
-    println!("Repo found at : {}", repo.path().to_string_lossy());
+    IssueUpdatePayload {
+        issue: IssueUpdate {
+            notes: notes.to_string(),
+            custom_fields,
+        },
+    }
+}
 
-    if repo.state() == RepositoryState::Clean {
-        println!("Repo is clean");
+/// Write the branch name back to the Redmine ticket: a note, and optionally a custom field (see
+/// `MyConfig::branch_custom_field_id`). In dry-run mode, prints the payload instead of sending
+/// it. Only call this when `create_new_branch` reports it actually created or switched to a
+/// branch — `notes` should describe which.
+fn notify_redmine(
+    ticket: i64,
+    notes: &str,
+    branch_name: &str,
+    key: String,
+    custom_field_id: u32,
+    run_type: RunType,
+) -> Result<(), reqwest::Error> {
+    let payload = build_issue_update_payload(notes, branch_name, custom_field_id);
+    let body = serde_json::to_string(&payload).unwrap();
+
+    if run_type == RunType::DryRun {
+        println!("Would PUT to issue #{} : {}", ticket, body);
+        return Ok(());
     }
 
-    let work = repo.diff_index_to_workdir(None, None)?;
+    let ticket_url = format!(
+        "https://redmine.corp.wallix.com/issues/{ticket}.json",
+        ticket = ticket
+    );
+
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()?;
+
+    client
+        .put(&ticket_url)
+        .header("X-Redmine-API-Key", key)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()?;
 
     println!(
-        "Number of files changed in workdir = {:?}",
-        work.stats()?.files_changed()
+        "Notified Redmine issue #{} about branch `{}`",
+        ticket, branch_name
     );
 
+    Ok(())
+}
+
+/// Which source branch a new ticket branch should be based on.
+#[derive(Debug, PartialEq)]
+enum SourceBranch {
+    Maintenance(String),
+    Parent(String),
+    Master(String),
+}
+
+/// Pick the branch a new ticket branch should be based on: a maintenance branch for the
+/// ticket's target version if one exists, otherwise a branch matching the ticket's parent if
+/// one exists, otherwise `master`. Pure function over branch names so it can be unit-tested
+/// without a `GitRepo` or touching the filesystem.
+fn determine_source_branch(
+    remote_name: &str,
+    target_version: &str,
+    parent_id: Option<i32>,
+    remote_branch_names: &[String],
+) -> SourceBranch {
+    let maintenance_branch_name = format!("{}/wab-{}", remote_name, target_version);
+
+    if remote_branch_names.iter().any(|b| b == &maintenance_branch_name) {
+        return SourceBranch::Maintenance(maintenance_branch_name);
+    }
+
+    if let Some(p) = parent_id {
+        if let Some(found) = remote_branch_names
+            .iter()
+            .find(|name| name.contains(&p.to_string()))
+        {
+            return SourceBranch::Parent(found.clone());
+        }
+    }
+
+    SourceBranch::Master(format!("{}/master", remote_name))
+}
+
+/// Whether `create_new_branch` should actually mutate the repo, or just print what it would do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunType {
+    Real,
+    DryRun,
+}
+
+/// Order remote branches by most recent commit first.
+fn sort_branches_by_recency(remote_branches: &[git_repo::Branch]) -> Vec<git_repo::Branch> {
+    let mut sorted = remote_branches.to_vec();
+    sorted.sort_by_key(|b| std::cmp::Reverse(b.time));
+    sorted
+}
+
+/// Let the user pick a base branch among all remote branches, ordered by most recent commit
+/// first. `default_branch` is highlighted by default.
+fn pick_base_branch(remote_branches: &[git_repo::Branch], default_branch: &str) -> String {
+    let sorted = sort_branches_by_recency(remote_branches);
+
+    let items: Vec<String> = sorted
+        .iter()
+        .map(|b| format!("{} ({})", b.name, format_branch_time(b.time)))
+        .collect();
+
+    let default_index = sorted
+        .iter()
+        .position(|b| b.name == default_branch)
+        .unwrap_or(0);
+
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Pick a branch to base the new branch on")
+        .default(default_index)
+        .items(&items)
+        .interact()
+        .unwrap();
+
+    sorted[selection].name.clone()
+}
+
+/// Render a commit timestamp as a rough "N days ago" relative time for display in the picker.
+fn format_branch_time(seconds: i64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(seconds);
+
+    let days_ago = (now - seconds) / 86400;
+
+    match days_ago {
+        d if d <= 0 => "today".to_string(),
+        1 => "1 day ago".to_string(),
+        d => format!("{} days ago", d),
+    }
+}
+
+/// What `create_new_branch` actually did, so callers can tell a real branch creation/switch
+/// apart from a no-op and notify Redmine (or not) accordingly.
+#[derive(Debug, PartialEq)]
+enum BranchAction {
+    /// We were already on the desired branch.
+    AlreadyOnBranch,
+    /// A branch for this ticket already existed; we switched (or, in dry-run, would switch) to it.
+    SwitchedToExisting(String),
+    /// We created (or, in dry-run, would create) a new branch.
+    Created(String),
+    /// No source branch could be found to base a new branch on, so nothing was done.
+    NoOp,
+}
+
+fn create_new_branch(
+    ticket: Ticket,
+    cfg: &MyConfig,
+    repo: &dyn GitRepo,
+    run_type: RunType,
+    pick_base: bool,
+    no_fetch: bool,
+) -> Result<BranchAction, GitRepoError> {
     let remotes = repo.remotes()?;
 
     if remotes.len() != 1 {
         panic!("I don't know what to do with more than one git remote repository")
     }
 
-    let remote_name = remotes.get(0).unwrap_or("origin");
-
-    let mut source_branch = format!("{}/{}", remote_name, "master");
+    let remote_name = &remotes[0];
 
-    let head = repo.head()?;
-    let head_ref = head.name().unwrap();
+    if no_fetch {
+        println!("Skipping fetch of {} (--no-fetch)", remote_name);
+    } else {
+        println!("Fetching {}...", remote_name);
+        if let Err(e) = repo.fetch(remote_name) {
+            println!(
+                "Warning: failed to fetch {} ({}), continuing with possibly stale refs",
+                remote_name, e
+            );
+        }
+    }
 
-    let remote_branches: Vec<String> = repo
-        .branches(Some(BranchType::Remote))?
-        .into_iter()
-        .filter_map(|b| {
-            if let Ok((branch, _)) = b {
-                if let Ok(Some(branch_name)) = branch.name() {
-                    return Some(branch_name.to_string());
-                }
-            }
-            return None;
-        })
-        .collect();
+    let head_ref = repo.current_branch()?;
 
-    // println!(
-    //     "List of all {} remote branchs {:?}",
-    //     remote_branchs.len(),
-    //     remote_branchs
-    // );
+    let remote_branches = repo.remote_branches()?;
+    let remote_branch_names: Vec<String> = remote_branches.iter().map(|b| b.name.clone()).collect();
 
-    if head_ref.ends_with(&ticket.issue.get_branch_name()) {
+    if head_ref.ends_with(&ticket.issue.get_branch_name(cfg)) {
         println!(
             "We are already in the desired branch {}",
-            ticket.issue.get_branch_name()
+            ticket.issue.get_branch_name(cfg)
         );
-        return Ok(());
+        return Ok(BranchAction::AlreadyOnBranch);
     }
 
     // Check if target branch already exists !
 
-    let branch_containing_this_ticket = remote_branches
-        .clone()
-        .into_iter()
+    let branch_containing_this_ticket = remote_branch_names
+        .iter()
         .find(|name| name.contains(&ticket.issue.id.to_string()));
 
     if let Some(existing_branch) = branch_containing_this_ticket {
-        println!(
-            "I could create branch {} but the branch {} already exists for the ticket #{}",
-            ticket.issue.get_branch_name(),
-            existing_branch,
-            ticket.issue.id,
-        );
-        return Ok(());
+        let branch = repo.find_branch(existing_branch)?.unwrap();
+        // `branch.name` is remote-qualified (e.g. "origin/rd-42-..."); checkout needs a local
+        // branch, so strip the remote prefix to get the name we'll track it under locally.
+        let local_branch_name = branch
+            .name
+            .strip_prefix(&format!("{}/", remote_name))
+            .unwrap_or(&branch.name)
+            .to_string();
+
+        match run_type {
+            RunType::DryRun => {
+                println!("Would switch to existing branch `{}`", local_branch_name);
+            }
+            RunType::Real => {
+                println!("Switching to existing branch `{}`", local_branch_name);
+                if !repo.local_branch_exists(&local_branch_name)? {
+                    repo.create_branch(&local_branch_name, &branch.name)?;
+                }
+                repo.checkout(&local_branch_name)?;
+            }
+        }
+        return Ok(BranchAction::SwitchedToExisting(local_branch_name));
     }
 
     println!("Target version : {}", ticket.issue.target_version());
 
-    let maintenance_branch_name = format!("{}/wab-{}", remote_name, ticket.issue.target_version());
+    let source_branch = determine_source_branch(
+        remote_name,
+        ticket.issue.target_version(),
+        ticket.issue.parent.as_ref().map(|p| p.id),
+        &remote_branch_names,
+    );
 
-    // Search if there is a maintenance branch for this version
-    let is_maintenance_branch_existing: bool = !remote_branches
-        .clone()
-        .into_iter()
-        .find(|b| maintenance_branch_name.eq(b))
-        .is_none();
+    let source_branch = match source_branch {
+        SourceBranch::Parent(parent_branch) => {
+            let master = format!("{}/master", remote_name);
+            let selections: &[&str] = &[&master, &parent_branch];
 
-    if is_maintenance_branch_existing {
-        source_branch = maintenance_branch_name;
-    } else {
-        if let Some(p) = &ticket.issue.parent {
-            let sources: Vec<String> = remote_branches
-                .into_iter()
-                .filter(|name| name.contains(&p.id.to_string()))
-                .collect();
-
-            if sources.len() > 0 {
-                let selections: &[&str] = &[&source_branch, &sources[0]];
-
-                let selection = Select::with_theme(&ColorfulTheme::default())
-                    .with_prompt("This ticket has a parent, what branch use to be based on ?")
-                    .default(0)
-                    .items(&selections[..])
-                    .interact()
-                    .unwrap();
-
-                source_branch = selections[selection].to_string();
-            } else {
-                println!(
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("This ticket has a parent, what branch use to be based on ?")
+                .default(1)
+                .items(selections)
+                .interact()
+                .unwrap();
+
+            selections[selection].to_string()
+        }
+        SourceBranch::Master(master) => {
+            match &ticket.issue.parent {
+                Some(p) => println!(
                     "This ticket has {} as parent but the branch don't exist",
-                    &p.id
-                )
+                    p.id
+                ),
+                None => println!("This ticket has no parent"),
+            }
+
+            if pick_base {
+                pick_base_branch(&remote_branches, &master)
+            } else {
+                master
             }
-        } else {
-            println!("This ticket has no parent")
         }
-    }
+        SourceBranch::Maintenance(name) => name,
+    };
 
-    for b in repo.branches(Some(BranchType::Remote))? {
-        let (b, _) = b?;
-        let name = b.name()?.unwrap();
+    if let Some(branch) = repo.find_branch(&source_branch)? {
+        println!("I found {} !", branch.name);
+        let name_new_branch = ticket.issue.get_branch_name(cfg);
 
-        if name == source_branch {
-            println!("I found {} !", name);
-            let reference = b.get();
-            let name_new_branch = ticket.issue.get_branch_name();
-            println!(
-                "Let's create branch {} based on {}",
-                name_new_branch, source_branch
-            );
-            let commit = reference.peel_to_commit()?;
-            // create the new branch based on this commit
-            repo.branch(&name_new_branch, &commit, false).unwrap();
-            //checkout to this branch
-            let obj = repo
-                .revparse_single(&format!("refs/heads/{}", name_new_branch))
-                .unwrap();
-            repo.checkout_tree(&obj, None)?;
-            return repo.set_head(&format!("refs/heads/{}", name_new_branch));
+        match run_type {
+            RunType::DryRun => {
+                println!(
+                    "Would create branch `{}` based on `{}`",
+                    name_new_branch, branch.name
+                );
+            }
+            RunType::Real => {
+                println!(
+                    "Let's create branch {} based on {}",
+                    name_new_branch, branch.name
+                );
+                repo.create_branch(&name_new_branch, &branch.name)?;
+                repo.checkout(&name_new_branch)?;
+            }
         }
+
+        return Ok(BranchAction::Created(name_new_branch));
     }
 
-    Ok(())
+    println!(
+        "Could not find source branch `{}` on the remote, nothing to do",
+        source_branch
+    );
+
+    Ok(BranchAction::NoOp)
 }
 
 fn main()-> Result<(), confy::ConfyError> {
@@ -285,12 +575,12 @@ fn main()-> Result<(), confy::ConfyError> {
         println!("Reading config in {:?}",config_path);
     }
 
-    if cfg.api_key == "" {
+    if cfg.api_key.is_empty() {
         println!("No api key found, storing default config file in {:?}...",config_path);
         confy::store(app_name, None, &cfg)?;
     }
 
-    let body = get_ticket_body(opt.ticket, cfg.api_key);
+    let body = get_ticket_body(opt.ticket, cfg.api_key.clone());
 
     let body = match body {
         Ok(body) => body,
@@ -304,11 +594,62 @@ fn main()-> Result<(), confy::ConfyError> {
         Err(e) => panic!("Unable to decode json \"{}\" => {}", body, e),
     };
 
-    if !opt.dry_run {
-        match create_new_branch(ticket) {
-            Ok(()) => {}
-            Err(e) => println!("Error : {}", e),
+    let run_type = if opt.dry_run {
+        RunType::DryRun
+    } else {
+        RunType::Real
+    };
+
+    let ticket_id = ticket.issue.id;
+
+    let backend_name = env::var(GIT_BACKEND_ENV_VAR).unwrap_or_else(|_| cfg.git_backend.clone());
+    let path = env::current_dir().unwrap();
+
+    match backend_from_name(&backend_name, &path)
+        .and_then(|repo| {
+            create_new_branch(
+                ticket,
+                &cfg,
+                repo.as_ref(),
+                run_type,
+                opt.pick_base,
+                opt.no_fetch,
+            )
+        })
+    {
+        Ok(action) => {
+            if !opt.notify {
+                return Ok(());
+            }
+
+            let notification = match action {
+                BranchAction::Created(branch_name) => {
+                    Some((format!("Branch `{}` created", branch_name), branch_name))
+                }
+                BranchAction::SwitchedToExisting(branch_name) => Some((
+                    format!("Branch `{}` already existed, switched to it", branch_name),
+                    branch_name,
+                )),
+                BranchAction::AlreadyOnBranch | BranchAction::NoOp => None,
+            };
+
+            match notification {
+                Some((notes, branch_name)) => {
+                    if let Err(e) = notify_redmine(
+                        ticket_id as i64,
+                        &notes,
+                        &branch_name,
+                        cfg.api_key.clone(),
+                        cfg.branch_custom_field_id,
+                        run_type,
+                    ) {
+                        println!("Error notifying Redmine : {}", e);
+                    }
+                }
+                None => println!("Nothing to notify Redmine about"),
+            }
         }
+        Err(e) => println!("Error : {}", e),
     }
 
     Ok(())
@@ -318,7 +659,7 @@ fn main()-> Result<(), confy::ConfyError> {
 mod tests {
 
     use super::*;
-    use serde_json;
+    use crate::git_repo::{Branch, FakeGitRepo};
 
     #[test]
     fn issue_parsing() -> Result<(), serde_json::Error> {
@@ -356,53 +697,328 @@ mod tests {
         }
         "#;
 
-        let result = read_issue(&example.to_string())?;
+        let result = read_issue(example)?;
 
         assert_eq!(result.issue.id, 26968);
 
         Ok(())
     }
 
+    fn sample_issue() -> Issue {
+        Issue {
+            id: 42,
+            subject: String::from("[Do] stuff \"asap\" "),
+            assigned_to: NamedProperty {
+                id: 220,
+                name: String::from("Arnold Bcon Tran"),
+            },
+            fixed_version: NamedProperty {
+                id: 318,
+                name: String::from("8.1.0"),
+            },
+            custom_fields: vec![
+                NamedPropertyWithOptionValue {
+                    id: 50,
+                    name: String::from("Developer"),
+                    value: Some(String::from("220")),
+                },
+                NamedPropertyWithOptionValue {
+                    id: 50,
+                    name: String::from("SF Case"),
+                    value: None,
+                },
+            ],
+            parent: None,
+        }
+    }
+
     #[test]
     fn test_branch_name() {
         let t = Ticket {
-            issue: Issue {
-                id: 42,
-                subject: String::from("[Do] stuff \"asap\" "),
-                assigned_to: NamedProperty {
-                    id: 220,
-                    name: String::from("Arnold Bcon Tran"),
-                },
-                fixed_version: NamedProperty {
-                    id: 318,
-                    name: String::from("8.1.0"),
-                },
-                custom_fields: vec![
-                    NamedPropertyWithOptionValue {
-                        id: 50,
-                        name: String::from("Developer"),
-                        value: Some(String::from("220")),
-                    },
-                    NamedPropertyWithOptionValue {
-                        id: 50,
-                        name: String::from("SF Case"),
-                        value: None,
-                    },
-                ],
-                parent: None,
-            },
+            issue: sample_issue(),
+        };
+        assert_eq!(
+            t.issue.get_branch_name(&MyConfig::default()),
+            "rd-42-abc-8.1-do-stuff-asap"
+        );
+    }
+
+    #[test]
+    fn test_branch_name_custom_template() {
+        let t = Ticket {
+            issue: sample_issue(),
+        };
+        let cfg = MyConfig {
+            branch_template: String::from("{version}/{id}-{subject}-{custom:Developer}"),
+            ..MyConfig::default()
+        };
+        assert_eq!(
+            t.issue.get_branch_name(&cfg),
+            "8.1/42-do-stuff-asap-220"
+        );
+    }
+
+    #[test]
+    fn test_branch_name_custom_separator_and_trigram() {
+        let t = Ticket {
+            issue: sample_issue(),
+        };
+        let cfg = MyConfig {
+            separator: String::from("_"),
+            trigram_format: String::from("{l2}{f1}"),
+            ..MyConfig::default()
         };
-        assert_eq!(t.issue.get_branch_name(), "rd-42-abc-8.1-do-stuff-asap");
+        assert_eq!(
+            t.issue.get_branch_name(&cfg),
+            "rd-42-bca-8.1-do_stuff_asap"
+        );
+    }
+
+    #[test]
+    fn test_branch_name_subject_max_len() {
+        let t = Ticket {
+            issue: sample_issue(),
+        };
+        let cfg = MyConfig {
+            subject_max_len: 5,
+            ..MyConfig::default()
+        };
+        assert_eq!(t.issue.get_branch_name(&cfg), "rd-42-abc-8.1-do-st");
+    }
+
+    #[test]
+    fn test_subject_cleanup_truncates_on_char_boundary_with_multibyte_chars() {
+        // "日本語" is 9 bytes / 3 chars; truncating at byte offset 2 would panic.
+        assert_eq!(
+            Issue::cleanup_subject(&String::from("日本語 test"), "-", 2),
+            "日本"
+        );
     }
 
     #[test]
     fn test_subject_cleanup() {
-        assert_eq!(Issue::cleanup_subject(&String::from("-----")), "-");
-        assert_eq!(Issue::cleanup_subject(&String::from("  - -  - -  ")), "-");
-        assert_eq!(Issue::cleanup_subject(&String::from("it's a clean()")), "its-a-clean");
         assert_eq!(
-            Issue::cleanup_subject(&String::from(" [Do] the - \"laundry\" ")),
+            Issue::cleanup_subject(&String::from("-----"), "-", 0),
+            "-"
+        );
+        assert_eq!(
+            Issue::cleanup_subject(&String::from("  - -  - -  "), "-", 0),
+            "-"
+        );
+        assert_eq!(
+            Issue::cleanup_subject(&String::from("it's a clean()"), "-", 0),
+            "its-a-clean"
+        );
+        assert_eq!(
+            Issue::cleanup_subject(&String::from(" [Do] the - \"laundry\" "), "-", 0),
             "do-the-laundry"
         );
     }
+
+    #[test]
+    fn source_branch_prefers_maintenance_branch() {
+        let branches = vec![
+            "origin/wab-8.1".to_string(),
+            "origin/rd-41-xyz-8.1-parent".to_string(),
+            "origin/master".to_string(),
+        ];
+
+        assert_eq!(
+            determine_source_branch("origin", "8.1", Some(41), &branches),
+            SourceBranch::Maintenance("origin/wab-8.1".to_string())
+        );
+    }
+
+    #[test]
+    fn source_branch_falls_back_to_parent_branch() {
+        let branches = vec![
+            "origin/rd-41-xyz-8.1-parent".to_string(),
+            "origin/master".to_string(),
+        ];
+
+        assert_eq!(
+            determine_source_branch("origin", "8.1", Some(41), &branches),
+            SourceBranch::Parent("origin/rd-41-xyz-8.1-parent".to_string())
+        );
+    }
+
+    #[test]
+    fn source_branch_falls_back_to_master() {
+        let branches = vec!["origin/master".to_string()];
+
+        assert_eq!(
+            determine_source_branch("origin", "8.1", None, &branches),
+            SourceBranch::Master("origin/master".to_string())
+        );
+
+        assert_eq!(
+            determine_source_branch("origin", "8.1", Some(99), &branches),
+            SourceBranch::Master("origin/master".to_string())
+        );
+    }
+
+    #[test]
+    fn branches_are_sorted_by_most_recent_commit_first() {
+        let branches = vec![
+            git_repo::Branch {
+                name: "origin/master".to_string(),
+                time: 100,
+            },
+            git_repo::Branch {
+                name: "origin/feature-a".to_string(),
+                time: 300,
+            },
+            git_repo::Branch {
+                name: "origin/feature-b".to_string(),
+                time: 200,
+            },
+        ];
+
+        let sorted: Vec<String> = sort_branches_by_recency(&branches)
+            .into_iter()
+            .map(|b| b.name)
+            .collect();
+
+        assert_eq!(
+            sorted,
+            vec!["origin/feature-a", "origin/feature-b", "origin/master"]
+        );
+    }
+
+    #[test]
+    fn issue_update_payload_without_custom_field() {
+        let payload = build_issue_update_payload(
+            "Branch `rd-42-abc-8.1-do-stuff` created",
+            "rd-42-abc-8.1-do-stuff",
+            0,
+        );
+        assert_eq!(payload.issue.notes, "Branch `rd-42-abc-8.1-do-stuff` created");
+        assert!(payload.issue.custom_fields.is_empty());
+    }
+
+    #[test]
+    fn issue_update_payload_with_custom_field() {
+        let payload = build_issue_update_payload(
+            "Branch `rd-42-abc-8.1-do-stuff` created",
+            "rd-42-abc-8.1-do-stuff",
+            16,
+        );
+        assert_eq!(payload.issue.custom_fields.len(), 1);
+        assert_eq!(payload.issue.custom_fields[0].id, 16);
+        assert_eq!(payload.issue.custom_fields[0].value, "rd-42-abc-8.1-do-stuff");
+    }
+
+    #[test]
+    fn dry_run_prints_plan_without_creating_or_checking_out() {
+        let ticket = Ticket {
+            issue: sample_issue(),
+        };
+        let repo = FakeGitRepo {
+            branches: vec![Branch {
+                name: "origin/wab-8.1".to_string(),
+                time: 100,
+            }],
+            ..Default::default()
+        };
+
+        let action = create_new_branch(
+            ticket,
+            &MyConfig::default(),
+            &repo,
+            RunType::DryRun,
+            false,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            action,
+            BranchAction::Created("rd-42-abc-8.1-do-stuff-asap".to_string())
+        );
+        assert!(repo.created_branches.borrow().is_empty());
+        assert!(repo.checked_out.borrow().is_empty());
+    }
+
+    #[test]
+    fn existing_ticket_branch_gets_a_local_tracking_branch_and_is_checked_out() {
+        let ticket = Ticket {
+            issue: sample_issue(),
+        };
+        let repo = FakeGitRepo {
+            branches: vec![
+                Branch {
+                    name: "origin/wab-8.1".to_string(),
+                    time: 100,
+                },
+                Branch {
+                    name: "origin/rd-42-abc-8.1-do-stuff-asap".to_string(),
+                    time: 200,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let action = create_new_branch(
+            ticket,
+            &MyConfig::default(),
+            &repo,
+            RunType::Real,
+            false,
+            true,
+        )
+        .unwrap();
+
+        // The remote-qualified branch name must be turned into a local branch before checkout:
+        // a real `GitRepo` backend can't check out "origin/rd-42-..." directly.
+        assert_eq!(
+            action,
+            BranchAction::SwitchedToExisting("rd-42-abc-8.1-do-stuff-asap".to_string())
+        );
+        assert_eq!(
+            *repo.created_branches.borrow(),
+            vec![(
+                "rd-42-abc-8.1-do-stuff-asap".to_string(),
+                "origin/rd-42-abc-8.1-do-stuff-asap".to_string()
+            )]
+        );
+        assert_eq!(
+            *repo.checked_out.borrow(),
+            vec!["rd-42-abc-8.1-do-stuff-asap".to_string()]
+        );
+    }
+
+    #[test]
+    fn existing_ticket_branch_with_local_copy_already_present_is_not_recreated() {
+        let ticket = Ticket {
+            issue: sample_issue(),
+        };
+        let repo = FakeGitRepo {
+            branches: vec![Branch {
+                name: "origin/rd-42-abc-8.1-do-stuff-asap".to_string(),
+                time: 200,
+            }],
+            existing_local_branches: vec!["rd-42-abc-8.1-do-stuff-asap".to_string()],
+            ..Default::default()
+        };
+
+        let action = create_new_branch(
+            ticket,
+            &MyConfig::default(),
+            &repo,
+            RunType::Real,
+            false,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            action,
+            BranchAction::SwitchedToExisting("rd-42-abc-8.1-do-stuff-asap".to_string())
+        );
+        assert!(repo.created_branches.borrow().is_empty());
+        assert_eq!(
+            *repo.checked_out.borrow(),
+            vec!["rd-42-abc-8.1-do-stuff-asap".to_string()]
+        );
+    }
 }